@@ -0,0 +1,63 @@
+use clap::{Parser, Subcommand};
+use kvs::common::{read_frame, write_frame, Request, Response};
+use kvs::Result;
+use std::net::TcpStream;
+use std::process::exit;
+
+const DEFAULT_ADDR: &str = "127.0.0.1:4000";
+
+#[derive(Parser, Debug)]
+#[command(name = env!("CARGO_PKG_NAME"), version, about, long_about = None)]
+struct Args {
+    #[command(subcommand)]
+    command: Commands,
+}
+
+#[derive(Subcommand, Debug)]
+enum Commands {
+    Get {
+        key: String,
+        #[arg(long, default_value = DEFAULT_ADDR)]
+        addr: String,
+    },
+    Set {
+        key: String,
+        value: String,
+        #[arg(long, default_value = DEFAULT_ADDR)]
+        addr: String,
+    },
+    Rm {
+        key: String,
+        #[arg(long, default_value = DEFAULT_ADDR)]
+        addr: String,
+    },
+}
+
+fn main() -> Result<()> {
+    let args = Args::parse();
+
+    let (addr, request) = match args.command {
+        Commands::Get { key, addr } => (addr, Request::Get { key }),
+        Commands::Set { key, value, addr } => (addr, Request::Set { key, value }),
+        Commands::Rm { key, addr } => (addr, Request::Rm { key }),
+    };
+
+    let stream = TcpStream::connect(addr)?;
+    write_frame(&stream, &request)?;
+    let response: Response = read_frame(&stream)?;
+
+    match response {
+        Response::Ok(Some(value)) => println!("{}", value),
+        Response::Ok(None) => {
+            if matches!(request, Request::Get { .. }) {
+                println!("Key not found");
+            }
+        }
+        Response::Err(message) => {
+            eprintln!("{}", message);
+            exit(1)
+        }
+    }
+
+    Ok(())
+}
@@ -0,0 +1,100 @@
+use clap::Parser;
+use failure::format_err;
+use kvs::common::{Request, Response};
+use kvs::thread_pool::{SharedQueueThreadPool, ThreadPool};
+use kvs::{Engine, KvStore, KvsEngine, Result, SledKvsEngine};
+use std::env::current_dir;
+use std::net::{TcpListener, TcpStream};
+use std::path::Path;
+
+const DEFAULT_ADDR: &str = "127.0.0.1:4000";
+const DEFAULT_ENGINE: Engine = Engine::Kvs;
+
+#[derive(Parser, Debug)]
+#[command(name = env!("CARGO_PKG_NAME"), version, about, long_about = None)]
+struct Args {
+    /// the address `kvs-server` listens for client connections on
+    #[arg(long, default_value = DEFAULT_ADDR)]
+    addr: String,
+
+    /// the storage backend to serve `kvs.db` with, `kvs` or `sled`;
+    /// defaults to whatever engine the data directory was created
+    /// with, falling back to `kvs` for a fresh directory
+    #[arg(long)]
+    engine: Option<Engine>,
+}
+
+fn main() -> Result<()> {
+    let args = Args::parse();
+    let dir = current_dir()?;
+    let engine = resolve_engine(&dir, args.engine)?;
+
+    let pool = SharedQueueThreadPool::new(num_cpus::get() as u32)?;
+    let listener = TcpListener::bind(&args.addr)?;
+
+    match engine {
+        Engine::Kvs => run(KvStore::open(dir)?, pool, listener),
+        Engine::Sled => run(SledKvsEngine::open(dir)?, pool, listener),
+    }
+}
+
+/// picks the engine to serve with: an explicit `--engine` flag wins,
+/// otherwise fall back to whatever the data directory already claims,
+/// defaulting to [`DEFAULT_ENGINE`] for a directory that has never
+/// been opened before
+fn resolve_engine(dir: &Path, requested: Option<Engine>) -> Result<Engine> {
+    let on_disk = kvs::engine_marker(dir)?;
+
+    match (requested, on_disk) {
+        (Some(requested), _) => Ok(requested),
+        (None, Some(on_disk)) => Ok(on_disk),
+        (None, None) => Ok(DEFAULT_ENGINE),
+    }
+}
+
+fn run(engine: impl KvsEngine, pool: impl ThreadPool, listener: TcpListener) -> Result<()> {
+    for stream in listener.incoming() {
+        let engine = engine.clone();
+        match stream {
+            Ok(stream) => pool.spawn(move || {
+                if let Err(error) = serve(engine, stream) {
+                    eprintln!("error handling connection: {}", error);
+                }
+            }),
+            Err(error) => eprintln!("connection failed: {}", error),
+        }
+    }
+
+    Ok(())
+}
+
+fn serve(engine: impl KvsEngine, stream: TcpStream) -> Result<()> {
+    let request: Request = kvs::common::read_frame(&stream)?;
+
+    let response = match request {
+        Request::Get { key } => match engine.get(key.into()) {
+            Ok(value) => match value.map(string_from_utf8).transpose() {
+                Ok(value) => Response::Ok(value),
+                Err(error) => Response::Err(error.to_string()),
+            },
+            Err(error) => Response::Err(error.to_string()),
+        },
+        Request::Set { key, value } => match engine.set(key.into(), value.into()) {
+            Ok(()) => Response::Ok(None),
+            Err(error) => Response::Err(error.to_string()),
+        },
+        Request::Rm { key } => match engine.remove(key.into()) {
+            Ok(()) => Response::Ok(None),
+            Err(error) => Response::Err(error.to_string()),
+        },
+    };
+
+    kvs::common::write_frame(&stream, &response)
+}
+
+/// the `Request`/`Response` wire protocol is string based, so a value
+/// returned by the (binary-safe) engine must be valid UTF-8 to be sent
+/// back to the client
+fn string_from_utf8(value: kvs::Bytes) -> Result<String> {
+    String::from_utf8(value.into_vec()).map_err(|_| format_err!("value is not valid utf-8"))
+}
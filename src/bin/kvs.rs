@@ -1,5 +1,6 @@
 use clap::{Parser, Subcommand};
-use kvs::{KvStore, Result};
+use kvs::{KvStore, KvsEngine, Result};
+use std::env::current_dir;
 use std::process::exit;
 
 #[derive(Parser, Debug)]
@@ -14,32 +15,40 @@ enum Commands {
     Get { key: String },
     Set { key: String, value: String },
     Rm { key: String },
+    /// migrate the data directory's WAL to the current on-disk format
+    Upgrade,
 }
 
 fn main() -> Result<()> {
     let args = Args::parse();
-    let mut kvs = KvStore::new();
+
+    if matches!(args.command, Commands::Upgrade) {
+        return KvStore::upgrade(current_dir()?);
+    }
+
+    let kvs = KvStore::open(current_dir()?)?;
 
     match &args.command {
         Commands::Get { key } => {
-            get_handler(key, &mut kvs);
+            get_handler(key, &kvs);
         }
         Commands::Set { key, value } => {
-            set_handler(key.to_string(), value.to_string(), &mut kvs);
+            set_handler(key.to_string(), value.to_string(), &kvs);
         }
         Commands::Rm { key } => {
-            rm_handler(key.to_string(), &mut kvs);
+            rm_handler(key.to_string(), &kvs);
         }
+        Commands::Upgrade => unreachable!("handled above"),
     }
 
     Ok(())
 }
 
-fn get_handler(value: &str, kvs: &mut KvStore) {
-    match kvs.get(value.to_string()) {
+fn get_handler(key: &str, kvs: &impl KvsEngine) {
+    match kvs.get(key.into()) {
         Ok(result) => match result {
-            Some(inner_result) => {
-                println!("{}", inner_result);
+            Some(value) => {
+                println!("{}", string_from_utf8(value));
                 exit(0)
             }
             None => {
@@ -54,8 +63,8 @@ fn get_handler(value: &str, kvs: &mut KvStore) {
     }
 }
 
-fn set_handler(key: String, value: String, kvs: &mut KvStore) {
-    match kvs.set(key, value) {
+fn set_handler(key: String, value: String, kvs: &impl KvsEngine) {
+    match kvs.set(key.into(), value.into()) {
         Ok(_) => exit(0),
         Err(error) => {
             println!("{}", error);
@@ -64,8 +73,8 @@ fn set_handler(key: String, value: String, kvs: &mut KvStore) {
     }
 }
 
-fn rm_handler(value: String, kvs: &mut KvStore) {
-    match kvs.remove(value) {
+fn rm_handler(key: String, kvs: &impl KvsEngine) {
+    match kvs.remove(key.into()) {
         Ok(_) => exit(0),
         Err(error) => {
             println!("{}", error);
@@ -73,3 +82,10 @@ fn rm_handler(value: String, kvs: &mut KvStore) {
         }
     }
 }
+
+/// the CLI and `kvs-client` only ever store valid UTF-8 strings, so a
+/// lossy conversion is just a defensive fallback for binary values
+/// written through the library's `Bytes` API
+fn string_from_utf8(value: kvs::Bytes) -> String {
+    String::from_utf8_lossy(&value.into_vec()).into_owned()
+}
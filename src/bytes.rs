@@ -0,0 +1,53 @@
+use serde::{Deserialize, Serialize};
+
+/// a binary-safe value that round-trips through the WAL regardless of
+/// its contents. unlike the `String` based convenience API the CLI and
+/// client expose, a [`Bytes`] can hold arbitrary bytes - embedded
+/// newlines, non-UTF-8 data, serialized structs, images - anything.
+#[derive(Debug, Clone, PartialEq, Eq, PartialOrd, Ord, Hash, Serialize, Deserialize)]
+pub struct Bytes(Vec<u8>);
+
+impl Bytes {
+    /// consumes the [`Bytes`], returning the underlying buffer
+    pub fn into_vec(self) -> Vec<u8> {
+        self.0
+    }
+}
+
+impl From<Vec<u8>> for Bytes {
+    fn from(bytes: Vec<u8>) -> Self {
+        Bytes(bytes)
+    }
+}
+
+impl From<&[u8]> for Bytes {
+    fn from(bytes: &[u8]) -> Self {
+        Bytes(bytes.to_vec())
+    }
+}
+
+impl From<String> for Bytes {
+    fn from(value: String) -> Self {
+        Bytes(value.into_bytes())
+    }
+}
+
+impl From<&str> for Bytes {
+    fn from(value: &str) -> Self {
+        Bytes(value.as_bytes().to_vec())
+    }
+}
+
+impl AsRef<[u8]> for Bytes {
+    fn as_ref(&self) -> &[u8] {
+        &self.0
+    }
+}
+
+impl std::ops::Deref for Bytes {
+    type Target = [u8];
+
+    fn deref(&self) -> &[u8] {
+        &self.0
+    }
+}
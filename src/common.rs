@@ -0,0 +1,76 @@
+//! the wire protocol spoken between `kvs-client` and `kvs-server`.
+//!
+//! every frame is a big-endian `u32` byte length followed by that many
+//! bytes of JSON, so a reader always knows exactly how much to buffer
+//! before handing the bytes to `serde_json` - the same `Get`/`Set`/`Rm`
+//! shape the WAL already uses, just carried over TCP instead of a file.
+
+use crate::Result;
+use byteorder::{BigEndian, ReadBytesExt, WriteBytesExt};
+use failure::format_err;
+use serde::{Deserialize, Serialize};
+use std::io::{Read, Write};
+
+/// an upper bound on a single frame's length, so a malformed or
+/// malicious length prefix can't force the reader into a multi-gigabyte
+/// allocation before any bytes have even been read off the wire
+const MAX_FRAME_LEN: u32 = 64 * 1024 * 1024;
+
+/// a command sent from `kvs-client` to `kvs-server`
+#[derive(Debug, Serialize, Deserialize)]
+pub enum Request {
+    /// get the value of a key
+    Get {
+        /// the key to look up
+        key: String,
+    },
+    /// set a key to a value, overwriting any existing value
+    Set {
+        /// the key to set
+        key: String,
+        /// the value to store
+        value: String,
+    },
+    /// remove a key
+    Rm {
+        /// the key to remove
+        key: String,
+    },
+}
+
+/// the result of applying a [`Request`] to the engine, sent back from
+/// `kvs-server` to `kvs-client`
+#[derive(Debug, Serialize, Deserialize)]
+pub enum Response {
+    /// the command applied successfully
+    Ok(Option<String>),
+    /// the command failed, carrying the error message
+    Err(String),
+}
+
+/// write `value` as a length-prefixed JSON frame
+pub fn write_frame<W: Write, T: Serialize>(mut writer: W, value: &T) -> Result<()> {
+    let payload = serde_json::to_vec(value)?;
+    writer.write_u32::<BigEndian>(payload.len() as u32)?;
+    writer.write_all(&payload)?;
+    writer.flush()?;
+
+    Ok(())
+}
+
+/// read a single length-prefixed JSON frame written by [`write_frame`]
+pub fn read_frame<R: Read, T: for<'de> Deserialize<'de>>(mut reader: R) -> Result<T> {
+    let len = reader.read_u32::<BigEndian>()?;
+    if len > MAX_FRAME_LEN {
+        return Err(format_err!(
+            "frame length {} exceeds the {} byte maximum",
+            len,
+            MAX_FRAME_LEN
+        ));
+    }
+
+    let mut payload = vec![0u8; len as usize];
+    reader.read_exact(&mut payload)?;
+
+    Ok(serde_json::from_slice(&payload)?)
+}
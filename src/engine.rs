@@ -0,0 +1,98 @@
+use crate::{Bytes, Result};
+use failure::format_err;
+use std::fmt;
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::str::FromStr;
+
+/// the interface a key value storage backend must implement so it can
+/// be driven by the `kvs` CLI and the `kvs-server` binary without
+/// either caller knowing which concrete engine is underneath.
+///
+/// keys and values are binary-safe [`Bytes`] - the `String` based
+/// convenience the CLI and client offer is a thin layer on top of this.
+///
+/// implementors take `&self` rather than `&mut self` and must be
+/// `Clone` + `Send` so a single engine can be shared across the
+/// threads a [`crate::thread_pool::ThreadPool`] hands connections to.
+pub trait KvsEngine: Clone + Send + 'static {
+    /// set the value of a key, overwriting any existing value
+    fn set(&self, key: Bytes, value: Bytes) -> Result<()>;
+
+    /// get the value of a key, returning `None` if the key does not exist
+    fn get(&self, key: Bytes) -> Result<Option<Bytes>>;
+
+    /// remove a key, returning an error if the key does not exist
+    fn remove(&self, key: Bytes) -> Result<()>;
+}
+
+/// the storage backends `kvs-server` can be started with. persisted
+/// alongside the data directory so reopening it with a different
+/// engine fails loudly instead of silently misreading the on-disk
+/// format.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Engine {
+    /// the bitcask-style WAL engine implemented by [`crate::KvStore`]
+    Kvs,
+    /// the [`sled`](https://docs.rs/sled) backed engine
+    Sled,
+}
+
+impl fmt::Display for Engine {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let name = match self {
+            Engine::Kvs => "kvs",
+            Engine::Sled => "sled",
+        };
+        write!(f, "{}", name)
+    }
+}
+
+impl FromStr for Engine {
+    type Err = failure::Error;
+
+    fn from_str(value: &str) -> Result<Self> {
+        match value {
+            "kvs" => Ok(Engine::Kvs),
+            "sled" => Ok(Engine::Sled),
+            other => Err(format_err!("unknown engine: {}", other)),
+        }
+    }
+}
+
+const ENGINE_MARKER_FILE: &str = "engine";
+
+/// reads the engine identity stamped in `dir` by a previous [`write_engine_marker`]
+/// call, or `None` if this data directory has never been opened before
+pub(crate) fn read_engine_marker(dir: impl AsRef<Path>) -> Result<Option<Engine>> {
+    let marker_path: PathBuf = dir.as_ref().join(ENGINE_MARKER_FILE);
+
+    if !marker_path.exists() {
+        return Ok(None);
+    }
+
+    let contents = fs::read_to_string(&marker_path)?;
+    Ok(Some(contents.trim().parse()?))
+}
+
+/// stamps `dir` with `engine` so a later open with a conflicting engine
+/// can be rejected instead of misreading the on-disk format
+pub(crate) fn write_engine_marker(dir: impl AsRef<Path>, engine: Engine) -> Result<()> {
+    fs::write(dir.as_ref().join(ENGINE_MARKER_FILE), engine.to_string())?;
+    Ok(())
+}
+
+/// confirms `dir` was last opened with `engine`, stamping it if this is
+/// the first time the directory has been used, and erroring if a
+/// different engine already claimed it
+pub(crate) fn check_engine_marker(dir: impl AsRef<Path>, engine: Engine) -> Result<()> {
+    match read_engine_marker(&dir)? {
+        Some(found) if found != engine => Err(format_err!(
+            "data directory was created with the '{}' engine, can't reopen it with '{}'",
+            found,
+            engine
+        )),
+        Some(_) => Ok(()),
+        None => write_engine_marker(&dir, engine),
+    }
+}
@@ -0,0 +1,51 @@
+//! a [`KvsEngine`] backed by [`sled`], kept alongside [`crate::KvStore`]
+//! as a second storage backend so the CLI and server can be pointed at
+//! either implementation without changing call sites.
+
+use crate::engine::{check_engine_marker, Engine};
+use crate::{Bytes, KvsEngine, Result};
+use failure::format_err;
+use std::path::PathBuf;
+
+/// a [`KvsEngine`] that delegates all storage to a [`sled::Db`]
+#[derive(Clone)]
+pub struct SledKvsEngine {
+    db: sled::Db,
+}
+
+impl SledKvsEngine {
+    /// opens (and creates, if necessary) a sled database rooted at `path`
+    pub fn open(path: impl Into<PathBuf>) -> Result<SledKvsEngine> {
+        let dir: PathBuf = path.into();
+        check_engine_marker(&dir, Engine::Sled)?;
+
+        let db = sled::open(&dir)?;
+        Ok(SledKvsEngine { db })
+    }
+}
+
+impl KvsEngine for SledKvsEngine {
+    fn set(&self, key: Bytes, value: Bytes) -> Result<()> {
+        self.db.insert(key.as_ref(), value.into_vec())?;
+        self.db.flush()?;
+
+        Ok(())
+    }
+
+    fn get(&self, key: Bytes) -> Result<Option<Bytes>> {
+        match self.db.get(key.as_ref())? {
+            Some(value) => Ok(Some(Bytes::from(value.to_vec()))),
+            None => Ok(None),
+        }
+    }
+
+    fn remove(&self, key: Bytes) -> Result<()> {
+        let removed = self.db.remove(key.as_ref())?;
+        self.db.flush()?;
+
+        match removed {
+            Some(_) => Ok(()),
+            None => Err(format_err!("Key not found")),
+        }
+    }
+}
@@ -0,0 +1,20 @@
+/// controls how eagerly a [`crate::KvStore`] forces its WAL writes to
+/// durable storage, trading throughput against the risk of losing the
+/// most recent writes in a crash.
+///
+/// only a writes-count interval ([`SyncPolicy::EveryN`]) is offered, not
+/// a time-based window - `kvs` has no background timer thread to drive
+/// one, and the explicit [`crate::KvStore::flush`] covers the "sync
+/// before I care" case in the meantime.
+#[derive(Debug, Clone, Copy)]
+pub enum SyncPolicy {
+    /// `fsync` after every `set`/`remove` - the slowest option, but a
+    /// crash can never lose an acknowledged write
+    Always,
+    /// never `fsync` on `set`/`remove`, only when [`crate::KvStore::flush`]
+    /// is called explicitly or the store is dropped. fastest, but a
+    /// crash can lose however many writes were buffered
+    Never,
+    /// `fsync` once every `n` writes
+    EveryN(u64),
+}
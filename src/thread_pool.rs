@@ -0,0 +1,140 @@
+//! thread pools used by `kvs-server` to run engine operations off the
+//! connection-accepting thread.
+
+use crate::Result;
+use crossbeam::channel::{self, Receiver, Sender};
+use std::thread;
+
+/// a pool of worker threads that jobs can be spawned onto
+pub trait ThreadPool {
+    /// create a new pool with `threads` worker threads
+    fn new(threads: u32) -> Result<Self>
+    where
+        Self: Sized;
+
+    /// run `job` on one of the pool's threads
+    ///
+    /// a job that panics must not take down the pool or leave it with
+    /// fewer workers than it started with
+    fn spawn<F>(&self, job: F)
+    where
+        F: FnOnce() + Send + 'static;
+}
+
+/// a [`ThreadPool`] that spawns a brand new thread for every job,
+/// useful as a baseline to compare pooled implementations against
+pub struct NaiveThreadPool;
+
+impl ThreadPool for NaiveThreadPool {
+    fn new(_threads: u32) -> Result<Self> {
+        Ok(NaiveThreadPool)
+    }
+
+    fn spawn<F>(&self, job: F)
+    where
+        F: FnOnce() + Send + 'static,
+    {
+        thread::spawn(job);
+    }
+}
+
+type Job = Box<dyn FnOnce() + Send + 'static>;
+
+/// a [`ThreadPool`] backed by a fixed number of worker threads pulling
+/// jobs off a shared queue
+pub struct SharedQueueThreadPool {
+    sender: Sender<Job>,
+}
+
+impl ThreadPool for SharedQueueThreadPool {
+    fn new(threads: u32) -> Result<Self> {
+        let (sender, receiver) = channel::unbounded::<Job>();
+
+        for _ in 0..threads {
+            spawn_worker(receiver.clone());
+        }
+
+        Ok(SharedQueueThreadPool { sender })
+    }
+
+    fn spawn<F>(&self, job: F)
+    where
+        F: FnOnce() + Send + 'static,
+    {
+        self.sender
+            .send(Box::new(job))
+            .expect("the shared queue's worker threads never stop");
+    }
+}
+
+/// spawns a single worker thread that pulls jobs off `receiver` until
+/// the channel is closed. if a job panics the worker thread unwinds
+/// out of `run_worker`, so [`Worker::drop`] notices and spins up a
+/// replacement rather than letting the pool quietly lose a thread.
+fn spawn_worker(receiver: Receiver<Job>) {
+    let worker = Worker { receiver };
+
+    thread::spawn(move || run_worker(worker));
+}
+
+struct Worker {
+    receiver: Receiver<Job>,
+}
+
+impl Clone for Worker {
+    fn clone(&self) -> Self {
+        Worker {
+            receiver: self.receiver.clone(),
+        }
+    }
+}
+
+impl Drop for Worker {
+    fn drop(&mut self) {
+        if thread::panicking() {
+            spawn_worker(self.receiver.clone());
+        }
+    }
+}
+
+fn run_worker(worker: Worker) {
+    loop {
+        match worker.receiver.recv() {
+            Ok(job) => job(),
+            Err(_) => return,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::sync::atomic::{AtomicUsize, Ordering};
+    use std::sync::Arc;
+    use std::time::Duration;
+
+    #[test]
+    fn a_panicking_job_does_not_shrink_the_pool() {
+        let pool = SharedQueueThreadPool::new(2).unwrap();
+        let completed = Arc::new(AtomicUsize::new(0));
+
+        for _ in 0..4 {
+            pool.spawn(|| panic!("boom"));
+        }
+
+        for _ in 0..8 {
+            let completed = Arc::clone(&completed);
+            pool.spawn(move || {
+                completed.fetch_add(1, Ordering::SeqCst);
+            });
+        }
+
+        // give the replacement workers time to spin up and drain the queue
+        let deadline = std::time::Instant::now() + Duration::from_secs(5);
+        while completed.load(Ordering::SeqCst) < 8 && std::time::Instant::now() < deadline {
+            thread::sleep(Duration::from_millis(10));
+        }
+
+        assert_eq!(completed.load(Ordering::SeqCst), 8);
+    }
+}